@@ -1,11 +1,29 @@
+use std::{
+    collections::BTreeMap,
+    io,
+    path::Path,
+    sync::mpsc::{channel, RecvTimeoutError},
+    thread,
+    time::{Duration, Instant},
+};
+
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use serde::Serialize;
+
 use crate::{
     github::actions::{
-        self, cmd, install, install_rust, pull_request, push, rust_toolchain, script, Platform,
-        Run, Rust, Step, Workflow,
+        self, cmd, install, install_rust_matrix, io_error, pull_request, push, rust_toolchain,
+        script, PlanStep, Platform, Run, RunningProcess, Rust, Step, Workflow,
     },
     WorkflowResult,
 };
 
+/// How long the workspace must be quiet (no new filesystem events) before a
+/// batch of changes triggers a re-run. Coalesces editor save storms and bulk
+/// `git` operations into a single run.
+const QUIET_PERIOD: Duration = Duration::from_millis(100);
+
 #[derive(Default)]
 pub struct CI(Vec<Tasks>);
 
@@ -33,31 +51,27 @@ impl CI {
     }
 
     pub fn standard_tests(mut self, rustc_version: &str) -> Self {
-        for platform in Platform::latest() {
-            self.0.push(
-                Tasks::new(
-                    "tests",
-                    platform,
-                    rust_toolchain(rustc_version).minimal().default().clippy(),
-                )
-                .tests(),
-            );
-        }
+        self.0.push(
+            Tasks::new_matrix(
+                "tests",
+                Platform::latest(),
+                [rust_toolchain(rustc_version).minimal().default().clippy()],
+            )
+            .tests(),
+        );
 
         self
     }
 
     pub fn standard_release_tests(mut self, rustc_version: &str) -> Self {
-        for platform in Platform::latest() {
-            self.0.push(
-                Tasks::new(
-                    "release-tests",
-                    platform,
-                    rust_toolchain(rustc_version).minimal().default(),
-                )
-                .release_tests(),
-            );
-        }
+        self.0.push(
+            Tasks::new_matrix(
+                "release-tests",
+                Platform::latest(),
+                [rust_toolchain(rustc_version).minimal().default()],
+            )
+            .release_tests(),
+        );
 
         self
     }
@@ -71,11 +85,23 @@ impl CI {
         self.0.push(tasks);
     }
 
-    pub fn write(self, check: bool) -> WorkflowResult<()> {
+    /// Write the generated workflow YAML, or, if `plan` is set, print
+    /// [`Self::plan`] as JSON instead and return without touching any files.
+    pub fn write(self, check: bool, plan: bool) -> WorkflowResult<()> {
+        if plan {
+            return self.print_plan();
+        }
+
         self.into_workflow().write(check)
     }
 
-    pub fn run(self) -> WorkflowResult<()> {
+    /// Run every job, or, if `plan` is set, print [`Self::plan`] as JSON
+    /// instead and return without running anything.
+    pub fn run(self, plan: bool) -> WorkflowResult<()> {
+        if plan {
+            return self.print_plan();
+        }
+
         for task in self.0 {
             task.run()?;
         }
@@ -83,15 +109,68 @@ impl CI {
         Ok(())
     }
 
+    /// Run every job once, then watch the workspace for file changes and
+    /// re-run the jobs for the current platform on each change, like a
+    /// continuous local CI loop.
+    pub fn watch(self) -> WorkflowResult<()> {
+        watch_tasks(self.0)
+    }
+
+    /// A structured, serializable description of every job this `CI` would
+    /// run: for each platform it covers, the ordered install steps and shell
+    /// commands, with their program/args/working directory/env. This mirrors
+    /// [`run`](Self::run) and the generated YAML without running anything or
+    /// writing any files.
+    pub fn plan(&self) -> Plan {
+        Plan {
+            jobs: self.0.iter().map(Tasks::plan).collect(),
+        }
+    }
+
+    /// Print [`Self::plan`] as JSON and return, performing no side effects.
+    pub fn print_plan(&self) -> WorkflowResult<()> {
+        let plan = serde_json::to_string_pretty(&self.plan()).map_err(io_error)?;
+        println!("{plan}");
+        Ok(())
+    }
+
     fn into_workflow(self) -> Workflow {
         let mut workflow = actions::workflow("ci-tests").on([push(), pull_request()]);
 
         for task in self.0 {
-            workflow.add_job(
-                &task.name,
-                task.platform,
-                task.tasks.into_iter().map(Step::from),
-            );
+            // The job-level env a cache strategy needs (e.g. sccache's GitHub
+            // Actions cache-service variables), as opposed to `cache_env`,
+            // which only carries what's safe to also run locally.
+            let job_env = task
+                .toolchains
+                .first()
+                .map(|rust| rust.cache_config().env())
+                .unwrap_or_default();
+            let steps = task.tasks.into_iter().map(Step::from);
+
+            if task.platforms.len() > 1 || task.toolchains.len() > 1 {
+                let mut dimensions = Vec::new();
+
+                if task.toolchains.len() > 1 {
+                    dimensions.push((
+                        "rust".to_string(),
+                        task.toolchains
+                            .iter()
+                            .map(|rust| rust.toolchain().to_string())
+                            .collect(),
+                    ));
+                }
+
+                workflow.add_matrix_job_with_env(
+                    &task.name,
+                    &task.platforms,
+                    dimensions,
+                    job_env,
+                    steps,
+                );
+            } else {
+                workflow.add_job_with_env(&task.name, task.platforms[0], job_env, steps);
+            }
         }
 
         workflow
@@ -100,32 +179,142 @@ impl CI {
 
 pub struct Tasks {
     name: String,
-    platform: Platform,
-    is_nightly: bool,
+    platforms: Vec<Platform>,
+    toolchains: Vec<Rust>,
     tasks: Vec<Task>,
+    // The locally-meaningful subset of the toolchains' `CacheConfig`
+    // environment (e.g. `RUSTC_WRAPPER=sccache`, but not the GitHub
+    // Actions-only cache-service variables), applied to every command added
+    // via `cmd`/`script` so the local run path honors the chosen cache
+    // strategy. The generated YAML gets the full `CacheConfig::env` instead,
+    // attached at the job level in `CI::into_workflow`.
+    cache_env: Vec<(String, String)>,
 }
 
 impl Tasks {
     pub fn new(name: impl Into<String>, platform: Platform, rust: Rust) -> Self {
+        Self::build(name, vec![platform], vec![rust])
+    }
+
+    /// A `Tasks` that runs across every combination of `platforms` and
+    /// `toolchains`, e.g. testing stable/beta/nightly across every platform
+    /// from one declaration. This becomes a single job with a `strategy.matrix`
+    /// in the generated workflow, instead of one duplicated job per
+    /// combination.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `toolchains` is empty: a job needs at least one toolchain to
+    /// install.
+    pub fn new_matrix(
+        name: impl Into<String>,
+        platforms: impl IntoIterator<Item = Platform>,
+        toolchains: impl IntoIterator<Item = Rust>,
+    ) -> Self {
+        Self::build(
+            name,
+            platforms.into_iter().collect(),
+            toolchains.into_iter().collect(),
+        )
+    }
+
+    fn build(name: impl Into<String>, platforms: Vec<Platform>, toolchains: Vec<Rust>) -> Self {
+        let install_step = install_rust_matrix(&toolchains);
+        // Every toolchain in a job shares one cache strategy, so the first
+        // toolchain's configuration speaks for the whole job. Only the
+        // locally-meaningful subset of its env applies here: the rest is CI
+        // (GitHub Actions)-only and gets attached at the job level instead,
+        // in `into_workflow`.
+        let cache_env = toolchains
+            .first()
+            .map(|rust| rust.cache_config().local_env())
+            .unwrap_or_default();
+
         Self {
             name: name.into(),
-            platform,
-            is_nightly: rust.is_nightly(),
+            platforms,
+            toolchains,
             tasks: Vec::new(),
+            cache_env,
         }
-        .step(install_rust(rust))
+        .step(install_step)
     }
 
     pub fn run(self) -> WorkflowResult<()> {
-        if self.platform.is_current() {
-            for task in self.tasks.into_iter() {
-                if let Task::Run(cmd) = task {
-                    cmd.run(self.is_nightly)?;
+        self.run_cancellable(&RunningProcess::new())?;
+        Ok(())
+    }
+
+    /// Watch the workspace for file changes and re-run these tasks on the
+    /// current platform on each change.
+    pub fn watch(self) -> WorkflowResult<()> {
+        watch_tasks(vec![self])
+    }
+
+    fn run_cancellable(&self, running: &RunningProcess) -> WorkflowResult<bool> {
+        if !self.platforms.iter().any(|platform| platform.is_current()) {
+            return Ok(true);
+        }
+
+        for rust in &self.toolchains {
+            // With more than one toolchain there's no single "default" to
+            // fall back on locally, so always pin the toolchain explicitly.
+            let toolchain =
+                (self.toolchains.len() > 1 || rust.is_nightly()).then(|| rust.toolchain());
+
+            // Tracks the first error from a `Task::Run`, like a failed step in
+            // a GitHub Actions job: later steps stop running, except for
+            // `if_failed`/`always` handlers, which still run before the
+            // original error is propagated.
+            let mut failure: Option<io::Error> = None;
+
+            for task in &self.tasks {
+                match task {
+                    Task::Run(cmd) if failure.is_none() => {
+                        match cmd.run_cancellable(toolchain, running) {
+                            Ok(true) => {}
+                            Ok(false) => return Ok(false),
+                            Err(err) => failure = Some(err),
+                        }
+                    }
+                    Task::Run(_) => {}
+                    Task::Install(step) => {
+                        match step.run_cancellable(failure.is_some(), toolchain, running) {
+                            Ok(true) => {}
+                            Ok(false) => return Ok(false),
+                            // Keep the original failure, if there was one:
+                            // a handler step's own error (e.g. failing to
+                            // spawn) shouldn't hide the failure it was
+                            // reacting to.
+                            Err(err) => {
+                                failure.get_or_insert(err);
+                            }
+                        }
+                    }
                 }
             }
+
+            if let Some(err) = failure {
+                return Err(err);
+            }
         }
 
-        Ok(())
+        Ok(true)
+    }
+
+    fn plan(&self) -> JobPlan {
+        let steps: Vec<PlanStep> = self.tasks.iter().flat_map(Task::plan).collect();
+
+        let platforms = self
+            .platforms
+            .iter()
+            .map(|platform| (platform.as_str().to_string(), steps.clone()))
+            .collect();
+
+        JobPlan {
+            name: self.name.clone(),
+            platforms,
+        }
     }
 
     pub fn step(mut self, step: Step) -> Self {
@@ -138,7 +327,8 @@ impl Tasks {
         program: impl Into<String>,
         args: impl IntoIterator<Item = impl Into<String>>,
     ) -> Self {
-        self.tasks.push(Task::Run(cmd(program, args)));
+        let run = self.with_cache_env(cmd(program, args));
+        self.tasks.push(Task::Run(run));
         self
     }
 
@@ -148,10 +338,17 @@ impl Tasks {
         Cmd: IntoIterator<Item = Arg>,
         Arg: Into<String>,
     {
-        self.tasks.push(Task::Run(script(cmds)));
+        let run = self.with_cache_env(script(cmds));
+        self.tasks.push(Task::Run(run));
         self
     }
 
+    fn with_cache_env(&self, run: Run) -> Run {
+        self.cache_env
+            .iter()
+            .fold(run, |run, (key, value)| run.local_env(key, value))
+    }
+
     pub fn tests(self) -> Self {
         self.cmd("cargo", ["xtask", "codegen", "--check"])
             .cmd(
@@ -187,6 +384,15 @@ enum Task {
     Run(Run),
 }
 
+impl Task {
+    fn plan(&self) -> Vec<PlanStep> {
+        match self {
+            Task::Install(step) => step.plan(),
+            Task::Run(run) => run.plan(),
+        }
+    }
+}
+
 impl From<Task> for Step {
     fn from(value: Task) -> Self {
         match value {
@@ -195,3 +401,120 @@ impl From<Task> for Step {
         }
     }
 }
+
+/// See [`CI::plan`].
+#[derive(Serialize)]
+pub struct Plan {
+    pub jobs: Vec<JobPlan>,
+}
+
+/// See [`CI::plan`]. `platforms` maps each platform this job covers to the
+/// ordered steps that would run on it.
+#[derive(Serialize)]
+pub struct JobPlan {
+    pub name: String,
+    pub platforms: BTreeMap<String, Vec<PlanStep>>,
+}
+
+fn watch_tasks(tasks: Vec<Tasks>) -> WorkflowResult<()> {
+    let ignore = workspace_ignore()?;
+    let (tx, rx) = channel();
+    let mut watcher: RecommendedWatcher =
+        notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+            if let Ok(event) = event {
+                let _ = tx.send(event);
+            }
+        })
+        .map_err(io_error)?;
+    watcher
+        .watch(Path::new("."), RecursiveMode::Recursive)
+        .map_err(io_error)?;
+
+    loop {
+        clear_screen();
+        let running = RunningProcess::new();
+
+        thread::scope(|scope| -> WorkflowResult<()> {
+            let cycle = scope.spawn(|| -> WorkflowResult<()> {
+                for task in &tasks {
+                    if !task.run_cancellable(&running)? {
+                        break;
+                    }
+                }
+
+                Ok(())
+            });
+
+            let wait_result = wait_for_changes(&rx, &ignore);
+            running.kill();
+
+            // A failing task (e.g. a red `cargo test`) is the whole point of
+            // a watch loop, not a reason to exit it: report it and keep
+            // watching. Only a genuine watcher failure, from `wait_result`,
+            // should end the loop.
+            if let Err(err) = cycle.join().expect("CI watch task panicked") {
+                eprintln!("CI task failed: {err}");
+            }
+
+            wait_result
+        })?;
+    }
+}
+
+fn wait_for_changes(
+    rx: &std::sync::mpsc::Receiver<notify::Event>,
+    ignore: &Gitignore,
+) -> WorkflowResult<()> {
+    loop {
+        let event = rx.recv().map_err(|_| disconnected())?;
+
+        if is_relevant(&event, ignore) {
+            break;
+        }
+    }
+
+    // Only a relevant event (not ignored `target/`/`.git/` churn) should
+    // restart the quiet period: otherwise a long-running build's own output
+    // would keep the timer from ever expiring.
+    let mut quiet_since = Instant::now();
+
+    loop {
+        let elapsed = quiet_since.elapsed();
+
+        if elapsed >= QUIET_PERIOD {
+            return Ok(());
+        }
+
+        match rx.recv_timeout(QUIET_PERIOD - elapsed) {
+            Ok(event) if is_relevant(&event, ignore) => quiet_since = Instant::now(),
+            Ok(_) => {}
+            Err(RecvTimeoutError::Timeout) => return Ok(()),
+            Err(RecvTimeoutError::Disconnected) => return Err(disconnected()),
+        }
+    }
+}
+
+fn is_relevant(event: &notify::Event, ignore: &Gitignore) -> bool {
+    event.paths.iter().any(|path| {
+        !ignore
+            .matched_path_or_any_parents(path, path.is_dir())
+            .is_ignore()
+    })
+}
+
+fn workspace_ignore() -> WorkflowResult<Gitignore> {
+    let mut builder = GitignoreBuilder::new(".");
+    builder.add_line(None, "target/").map_err(io_error)?;
+    builder.add_line(None, ".git/").map_err(io_error)?;
+    let _ = builder.add(".gitignore");
+
+    builder.build().map_err(io_error)
+}
+
+fn clear_screen() {
+    print!("\x1B[2J\x1B[1;1H");
+}
+
+fn disconnected() -> io::Error {
+    io::Error::new(io::ErrorKind::Other, "file watcher disconnected")
+}
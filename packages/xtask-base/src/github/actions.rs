@@ -1,15 +1,42 @@
-use std::{env::consts::OS, fmt, path::PathBuf};
+use std::{
+    env::consts::OS,
+    fmt, io,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
 
 use crate::{update_file, WorkflowResult};
 
 pub fn install_rust(rust: Rust) -> Step {
-    Step(StepEnum::Multi(
-        [checkout(), rust.into(), rust_cache()]
-            .into_iter()
-            .collect(),
+    let cache_step = rust.cache.step();
+
+    Step::from_enum(StepEnum::Multi(
+        [checkout(), rust.into(), cache_step].into_iter().collect(),
     ))
 }
 
+/// Like [`install_rust`], but for a job spanning a matrix of toolchains: the
+/// generated step installs `${{ matrix.rust }}` rather than a fixed version,
+/// taking the rest of its configuration (profile, components, targets) from
+/// `toolchains[0]`.
+pub(crate) fn install_rust_matrix(toolchains: &[Rust]) -> Step {
+    assert!(
+        !toolchains.is_empty(),
+        "Tasks::new_matrix needs at least one toolchain"
+    );
+
+    let mut rust = toolchains[0].clone();
+
+    if toolchains.len() > 1 {
+        rust.toolchain = "${{ matrix.rust }}".to_string();
+    }
+
+    install_rust(rust)
+}
+
 #[must_use]
 pub struct Workflow {
     name: String,
@@ -37,7 +64,20 @@ impl Workflow {
         runs_on: Platform,
         steps: impl IntoIterator<Item = impl Into<Step>>,
     ) {
-        self.jobs.push(Job::new(name, runs_on, steps));
+        self.add_job_with_env(name, runs_on, Vec::new(), steps);
+    }
+
+    /// Like [`Self::add_job`], but also sets job-level environment variables,
+    /// e.g. the ones [`CacheConfig::env`] requires for a `sccache`-backed
+    /// job.
+    pub(crate) fn add_job_with_env(
+        &mut self,
+        name: &str,
+        runs_on: Platform,
+        env: Vec<(String, String)>,
+        steps: impl IntoIterator<Item = impl Into<Step>>,
+    ) {
+        self.jobs.push(Job::new(name, runs_on, env, steps));
     }
 
     pub fn job(
@@ -50,6 +90,34 @@ impl Workflow {
         self
     }
 
+    /// Add a single job that runs across every combination of `platforms`
+    /// and any extra matrix dimensions, instead of one duplicated job per
+    /// combination. `platforms` becomes the `os` dimension and `runs-on`.
+    pub fn add_matrix_job(
+        &mut self,
+        name: &str,
+        platforms: &[Platform],
+        dimensions: Vec<(String, Vec<String>)>,
+        steps: impl IntoIterator<Item = impl Into<Step>>,
+    ) {
+        self.add_matrix_job_with_env(name, platforms, dimensions, Vec::new(), steps);
+    }
+
+    /// Like [`Self::add_matrix_job`], but also sets job-level environment
+    /// variables, e.g. the ones [`CacheConfig::env`] requires for a
+    /// `sccache`-backed job.
+    pub(crate) fn add_matrix_job_with_env(
+        &mut self,
+        name: &str,
+        platforms: &[Platform],
+        dimensions: Vec<(String, Vec<String>)>,
+        env: Vec<(String, String)>,
+        steps: impl IntoIterator<Item = impl Into<Step>>,
+    ) {
+        self.jobs
+            .push(Job::new_matrix(name, platforms, dimensions, env, steps));
+    }
+
     pub fn write(&self, check: bool) -> WorkflowResult<()> {
         update_file(
             [".github", "workflows", &format!("{}.yml", self.name)]
@@ -84,19 +152,56 @@ impl fmt::Display for Workflow {
 
 struct Job {
     name: String,
-    runs_on: Platform,
+    runs_on: RunsOn,
+    matrix: Vec<(String, Vec<String>)>,
+    env: Vec<(String, String)>,
     steps: Vec<Step>,
 }
 
+enum RunsOn {
+    Platform(Platform),
+    Matrix,
+}
+
 impl Job {
     fn new(
         name: &str,
         runs_on: Platform,
+        env: Vec<(String, String)>,
         steps: impl IntoIterator<Item = impl Into<Step>>,
     ) -> Self {
         Self {
             name: name.to_string(),
-            runs_on,
+            runs_on: RunsOn::Platform(runs_on),
+            matrix: Vec::new(),
+            env,
+            steps: steps.into_iter().map(Into::into).collect(),
+        }
+    }
+
+    fn new_matrix(
+        name: &str,
+        platforms: &[Platform],
+        mut dimensions: Vec<(String, Vec<String>)>,
+        env: Vec<(String, String)>,
+        steps: impl IntoIterator<Item = impl Into<Step>>,
+    ) -> Self {
+        dimensions.insert(
+            0,
+            (
+                "os".to_string(),
+                platforms
+                    .iter()
+                    .map(|platform| platform.as_str().to_string())
+                    .collect(),
+            ),
+        );
+
+        Self {
+            name: name.to_string(),
+            runs_on: RunsOn::Matrix,
+            matrix: dimensions,
+            env,
             steps: steps.into_iter().map(Into::into).collect(),
         }
     }
@@ -104,9 +209,32 @@ impl Job {
 
 impl fmt::Display for Job {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let runs_on = self.runs_on.as_str();
-        writeln!(f, "  {}-{}:", self.name, runs_on)?;
-        writeln!(f, "    runs-on: {}", runs_on)?;
+        match &self.runs_on {
+            RunsOn::Platform(platform) => writeln!(f, "  {}-{}:", self.name, platform.as_str())?,
+            RunsOn::Matrix => writeln!(f, "  {}:", self.name)?,
+        }
+
+        match &self.runs_on {
+            RunsOn::Platform(platform) => writeln!(f, "    runs-on: {}", platform.as_str())?,
+            RunsOn::Matrix => writeln!(f, "    runs-on: ${{{{ matrix.os }}}}")?,
+        }
+
+        if !self.matrix.is_empty() {
+            f.write_str("    strategy:\n      matrix:\n")?;
+
+            for (key, values) in &self.matrix {
+                writeln!(f, "        {key}: [{}]", values.join(", "))?;
+            }
+        }
+
+        if !self.env.is_empty() {
+            f.write_str("    env:\n")?;
+
+            for (key, value) in &self.env {
+                writeln!(f, "      {key}: {}", yaml_quote(value))?;
+            }
+        }
+
         f.write_str("    steps:\n")?;
 
         for step in &self.steps {
@@ -152,7 +280,7 @@ impl Platform {
         }
     }
 
-    fn as_str(self) -> &'static str {
+    pub(crate) fn as_str(self) -> &'static str {
         match self {
             Platform::UbuntuLatest => "ubuntu-latest",
             Platform::MacOSLatest => "macos-latest",
@@ -177,10 +305,14 @@ impl Action {
     }
 }
 
-impl fmt::Display for Action {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+impl Action {
+    fn fmt_step(&self, f: &mut fmt::Formatter<'_>, condition: Option<&str>) -> fmt::Result {
         writeln!(f, "    - uses: {}", self.uses)?;
 
+        if let Some(condition) = condition {
+            writeln!(f, "      if: {condition}")?;
+        }
+
         if !self.with.is_empty() {
             f.write_str("      with:\n")?;
 
@@ -206,33 +338,187 @@ pub fn checkout() -> Step {
 
 impl From<Action> for Step {
     fn from(value: Action) -> Self {
-        Step(StepEnum::Action(value))
+        Step::from_enum(StepEnum::Action(value))
     }
 }
 
-pub struct Step(StepEnum);
+pub struct Step {
+    condition: Option<Condition>,
+    inner: StepEnum,
+}
 
 pub fn multi_step(steps: impl IntoIterator<Item = impl Into<Step>>) -> Step {
-    Step(StepEnum::Multi(steps.into_iter().map(Into::into).collect()))
+    Step::from_enum(StepEnum::Multi(steps.into_iter().map(Into::into).collect()))
+}
+
+/// A GitHub Actions `if:` expression, also consulted by the local
+/// `Tasks::run` path so failure handlers behave the same way locally as in
+/// CI. [`Condition::Expr`] is GitHub Actions-only: it never runs locally,
+/// since there's no local equivalent of the workflow context it references.
+enum Condition {
+    Failure,
+    Always,
+    Expr(String),
+}
+
+impl Condition {
+    fn as_expr(&self) -> &str {
+        match self {
+            Condition::Failure => "failure()",
+            Condition::Always => "always()",
+            Condition::Expr(expr) => expr,
+        }
+    }
+}
+
+/// Whether a step with `condition` should run locally given that an earlier
+/// step in the same [`Tasks`](crate::ci::Tasks) has already failed, matching
+/// the CI semantics of that `if:` condition: no condition runs only while
+/// nothing has failed yet, like GitHub Actions' default `if: success()`.
+fn runs_after_failure(condition: Option<&Condition>, failed: bool) -> bool {
+    match condition {
+        Some(Condition::Always) => true,
+        Some(Condition::Failure) => failed,
+        Some(Condition::Expr(_)) => false,
+        None => !failed,
+    }
 }
 
 impl Step {
-    pub fn if_failed(self) -> Self {
+    fn from_enum(inner: StepEnum) -> Self {
+        Self {
+            condition: None,
+            inner,
+        }
+    }
+
+    /// Only run this step if an earlier step in the same job failed, like
+    /// `if: failure()`. Typically used for diagnostics (uploading logs,
+    /// printing output) that should only run on failure.
+    pub fn if_failed(mut self) -> Self {
+        self.condition = Some(Condition::Failure);
+        self
+    }
+
+    /// Always run this step, even if an earlier step in the same job failed,
+    /// like `if: always()`.
+    pub fn always(mut self) -> Self {
+        self.condition = Some(Condition::Always);
         self
     }
+
+    /// Only run this step when `expr` (a GitHub Actions expression, e.g.
+    /// `"github.ref == 'refs/heads/main'"`) evaluates to true.
+    pub fn when_expr(mut self, expr: impl Into<String>) -> Self {
+        self.condition = Some(Condition::Expr(expr.into()));
+        self
+    }
+
+    /// Run this step locally, like [`Run::run_cancellable`]. Actions have no
+    /// local equivalent, so only the `Run`-backed parts of this step (if
+    /// any) actually execute, gated by whichever condition applies to it:
+    /// its own if it set one, otherwise the nearest enclosing [`multi_step`]
+    /// ancestor's. `failed` is whether an earlier step in the same
+    /// [`Tasks`](crate::ci::Tasks) has already failed.
+    pub(crate) fn run_cancellable(
+        &self,
+        failed: bool,
+        toolchain: Option<&str>,
+        running: &RunningProcess,
+    ) -> WorkflowResult<bool> {
+        self.run_cancellable_with(self.condition.as_ref(), failed, toolchain, running)
+    }
+
+    fn run_cancellable_with(
+        &self,
+        condition: Option<&Condition>,
+        failed: bool,
+        toolchain: Option<&str>,
+        running: &RunningProcess,
+    ) -> WorkflowResult<bool> {
+        match &self.inner {
+            StepEnum::Empty | StepEnum::Action(_) => Ok(true),
+            StepEnum::Multi(steps) => {
+                for step in steps {
+                    let condition = step.condition.as_ref().or(condition);
+
+                    if !step.run_cancellable_with(condition, failed, toolchain, running)? {
+                        return Ok(false);
+                    }
+                }
+
+                Ok(true)
+            }
+            StepEnum::Run(run) => {
+                if !runs_after_failure(condition, failed) {
+                    return Ok(true);
+                }
+
+                run.run_cancellable(toolchain, running)
+            }
+        }
+    }
+
+    /// Flatten this step into its planned [`PlanStep`]s, for [`CI::plan`](crate::ci::CI::plan).
+    pub(crate) fn plan(&self) -> Vec<PlanStep> {
+        match &self.inner {
+            StepEnum::Empty => Vec::new(),
+            StepEnum::Multi(steps) => steps.iter().flat_map(Step::plan).collect(),
+            StepEnum::Action(action) => vec![PlanStep::Action {
+                uses: action.uses.clone(),
+                with: action.with.clone(),
+            }],
+            StepEnum::Run(run) => run.plan(),
+        }
+    }
+}
+
+/// A single planned install step or shell command, as emitted by
+/// [`CI::plan`](crate::ci::CI::plan).
+#[derive(Clone, serde::Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum PlanStep {
+    Action {
+        uses: String,
+        with: Vec<(String, String)>,
+    },
+    Command {
+        program: String,
+        args: Vec<String>,
+        working_dir: Option<String>,
+        env: Vec<(String, String)>,
+    },
 }
 
 impl fmt::Display for Step {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match &self.0 {
+        let condition = self.condition.as_ref().map(Condition::as_expr);
+        self.fmt_with_condition(f, condition)
+    }
+}
+
+impl Step {
+    fn fmt_with_condition(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+        condition: Option<&str>,
+    ) -> fmt::Result {
+        match &self.inner {
             StepEnum::Empty => (),
             StepEnum::Multi(steps) => {
                 for step in steps {
-                    step.fmt(f)?;
+                    // A child keeps its own condition if it set one, rather
+                    // than being overridden by the parent's.
+                    let condition = step
+                        .condition
+                        .as_ref()
+                        .map(Condition::as_expr)
+                        .or(condition);
+                    step.fmt_with_condition(f, condition)?;
                 }
             }
-            StepEnum::Action(action) => action.fmt(f)?,
-            StepEnum::Run(run) => run.fmt(f)?,
+            StepEnum::Action(action) => action.fmt_step(f, condition)?,
+            StepEnum::Run(run) => run.fmt_step(f, condition)?,
         }
 
         Ok(())
@@ -254,7 +540,7 @@ pub fn upload_artifact(name: &str, path: &str) -> Step {
 }
 
 pub fn rust_cache() -> Step {
-    action("Swatinem/rust-cache@v2").into()
+    CacheConfig::rust_cache().step()
 }
 
 pub fn install(crate_name: &str, version: &str) -> Step {
@@ -265,12 +551,154 @@ pub fn install(crate_name: &str, version: &str) -> Step {
     .into()
 }
 
+/// How a [`Rust`] toolchain's compiler cache is populated, installed as part
+/// of [`install_rust`]. Defaults to [`CacheConfig::rust_cache`], restoring a
+/// `target/` directory from a previous run; [`CacheConfig::sccache`] instead
+/// shares compiled objects across jobs, which works better for matrix builds
+/// where a `target/` directory can't be shared between toolchains/platforms.
+#[derive(Clone)]
+pub struct CacheConfig {
+    strategy: CacheStrategy,
+    key_prefix: Option<String>,
+    shared_key: Option<String>,
+    cache_cargo_home: bool,
+    cache_target: bool,
+}
+
+#[derive(Clone)]
+enum CacheStrategy {
+    RustCache,
+    Sccache,
+}
+
+impl CacheConfig {
+    pub fn rust_cache() -> Self {
+        Self {
+            strategy: CacheStrategy::RustCache,
+            key_prefix: None,
+            shared_key: None,
+            cache_cargo_home: true,
+            cache_target: true,
+        }
+    }
+
+    /// Cache compiled objects in `sccache`, installed via
+    /// `mozilla-actions/sccache-action`, instead of restoring a whole
+    /// `target/` directory. Subsequent `cargo` invocations must run with
+    /// `RUSTC_WRAPPER=sccache` set. Once a job's first toolchain uses this
+    /// strategy, the generated YAML sets it for the whole job, and the local
+    /// run path sets it for every command added via
+    /// [`Tasks`](crate::ci::Tasks)' `cmd`/`script`; commands inside a step
+    /// added via `Tasks::step` (e.g. [`install`]) run locally without it.
+    pub fn sccache() -> Self {
+        Self {
+            strategy: CacheStrategy::Sccache,
+            key_prefix: None,
+            shared_key: None,
+            cache_cargo_home: true,
+            cache_target: false,
+        }
+    }
+
+    /// A prefix folded into the cache key, so unrelated workflows sharing a
+    /// cache backend don't collide. Ignored by [`CacheConfig::sccache`],
+    /// which keys its cache by content hash rather than a workflow-chosen
+    /// string.
+    pub fn key_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.key_prefix = Some(prefix.into());
+        self
+    }
+
+    /// Use the same cache key across every job, instead of one derived per
+    /// job, e.g. so a matrix of toolchains/platforms shares a single cache.
+    /// Ignored by [`CacheConfig::sccache`], for the same reason as
+    /// [`key_prefix`](Self::key_prefix).
+    pub fn shared_key(mut self, key: impl Into<String>) -> Self {
+        self.shared_key = Some(key.into());
+        self
+    }
+
+    /// Whether to cache `~/.cargo` (the registry index, crate sources and
+    /// git checkouts). Defaults to `true`.
+    pub fn cache_cargo_home(mut self, cache: bool) -> Self {
+        self.cache_cargo_home = cache;
+        self
+    }
+
+    /// Whether to cache the `target/` directory. Defaults to `true` for
+    /// [`CacheConfig::rust_cache`]; ignored by [`CacheConfig::sccache`],
+    /// which caches compiled objects itself instead of the whole directory.
+    pub fn cache_target(mut self, cache: bool) -> Self {
+        self.cache_target = cache;
+        self
+    }
+
+    fn step(&self) -> Step {
+        match self.strategy {
+            CacheStrategy::RustCache => {
+                let mut action = action("Swatinem/rust-cache@v2");
+
+                if let Some(prefix) = &self.key_prefix {
+                    action.add_with("prefix-key", prefix);
+                }
+
+                if let Some(key) = &self.shared_key {
+                    action.add_with("shared-key", key);
+                }
+
+                action.add_with("cache-bin", self.cache_cargo_home);
+                action.add_with("cache-targets", self.cache_target);
+
+                action.into()
+            }
+            CacheStrategy::Sccache => action("mozilla-actions/sccache-action@v0.0.5").into(),
+        }
+    }
+
+    /// Environment variables every `cargo` invocation needs once this cache
+    /// strategy is active, for the generated YAML. Empty for
+    /// [`CacheConfig::rust_cache`], which needs no special environment.
+    /// Includes [`Self::local_env`] plus GitHub Actions-only variables that
+    /// point `sccache` at the runner's cache service; see [`Self::local_env`]
+    /// for why those can't be used locally.
+    pub(crate) fn env(&self) -> Vec<(String, String)> {
+        match self.strategy {
+            CacheStrategy::RustCache => Vec::new(),
+            CacheStrategy::Sccache => {
+                let mut env = self.local_env();
+                env.push(("SCCACHE_GHA_ENABLED".to_string(), "true".to_string()));
+                env.push((
+                    "ACTIONS_CACHE_URL".to_string(),
+                    "${{ env.ACTIONS_CACHE_URL }}".to_string(),
+                ));
+                env
+            }
+        }
+    }
+
+    /// The subset of [`Self::env`] that's meaningful outside GitHub Actions,
+    /// for the local `Tasks::run` path. `SCCACHE_GHA_ENABLED` and
+    /// `ACTIONS_CACHE_URL` configure `sccache` to use the GitHub Actions cache
+    /// service; there's no local equivalent, and a literal
+    /// `${{ env.ACTIONS_CACHE_URL }}` string (GitHub Actions expressions
+    /// aren't substituted locally) would just make `sccache` fail to reach
+    /// that backend on every local build.
+    pub(crate) fn local_env(&self) -> Vec<(String, String)> {
+        match self.strategy {
+            CacheStrategy::RustCache => Vec::new(),
+            CacheStrategy::Sccache => vec![("RUSTC_WRAPPER".to_string(), "sccache".to_string())],
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct Rust {
     toolchain: String,
     profile: Option<&'static str>,
     default: bool,
     components: Vec<&'static str>,
     targets: Option<Vec<String>>,
+    cache: CacheConfig,
 }
 
 pub fn rust_toolchain(version: &str) -> Rust {
@@ -280,10 +708,19 @@ pub fn rust_toolchain(version: &str) -> Rust {
         default: false,
         components: Vec::new(),
         targets: None,
+        cache: CacheConfig::rust_cache(),
     }
 }
 
 impl Rust {
+    pub(crate) fn toolchain(&self) -> &str {
+        &self.toolchain
+    }
+
+    pub(crate) fn cache_config(&self) -> &CacheConfig {
+        &self.cache
+    }
+
     pub fn is_nightly(&self) -> bool {
         self.toolchain.starts_with("nightly")
     }
@@ -314,6 +751,14 @@ impl Rust {
         self.components.push("rustfmt");
         self
     }
+
+    /// Choose how this toolchain's compiler cache is populated, e.g.
+    /// [`CacheConfig::sccache`] instead of the default
+    /// [`CacheConfig::rust_cache`].
+    pub fn cache(mut self, cache: CacheConfig) -> Self {
+        self.cache = cache;
+        self
+    }
 }
 
 impl From<Rust> for Step {
@@ -343,12 +788,26 @@ impl From<Rust> for Step {
 pub struct Run {
     script: RunEnum,
     directory: Option<String>,
+    env: Vec<(String, EnvValue)>,
+    // Env set only for the local `run` path, e.g. a cache wrapper's env
+    // that's already attached at the job level in the generated YAML, so
+    // rendering it again per-step would just be a redundant duplicate.
+    local_env: Vec<(String, String)>,
+    stdin: Option<Vec<u8>>,
+}
+
+enum EnvValue {
+    Set(String),
+    Remove,
 }
 
 pub fn cmd(program: impl Into<String>, args: impl IntoIterator<Item = impl Into<String>>) -> Run {
     Run {
         script: RunEnum::Single(Cmd::new(program, args)),
         directory: None,
+        env: Vec::new(),
+        local_env: Vec::new(),
+        stdin: None,
     }
 }
 
@@ -361,6 +820,9 @@ where
     Run {
         script: RunEnum::Multi(lines.into_iter().map(Into::into).collect()),
         directory: None,
+        env: Vec::new(),
+        local_env: Vec::new(),
+        stdin: None,
     }
 }
 
@@ -370,49 +832,228 @@ impl Run {
         self
     }
 
-    pub fn run(&self, is_nightly: bool) -> WorkflowResult<()> {
+    /// Set an environment variable for this step, e.g. `RUSTFLAGS` or
+    /// `CARGO_TARGET_DIR`.
+    pub fn env(mut self, key: &str, value: impl fmt::Display) -> Self {
+        self.env
+            .push((key.to_string(), EnvValue::Set(value.to_string())));
+        self
+    }
+
+    /// Unset an inherited environment variable for the local `run` path.
+    /// GitHub Actions has no way to unset a step's environment, so this has
+    /// no effect on the generated YAML.
+    pub fn env_remove(mut self, key: &str) -> Self {
+        self.env.push((key.to_string(), EnvValue::Remove));
+        self
+    }
+
+    /// Like [`Self::env`], but only for the local `run` path: it's left out
+    /// of the generated YAML entirely, for a variable that's already set
+    /// some other way there (e.g. [`CacheConfig::env`] at the job level).
+    pub(crate) fn local_env(mut self, key: &str, value: &str) -> Self {
+        self.local_env.push((key.to_string(), value.to_string()));
+        self
+    }
+
+    /// Pipe `input` to the command's stdin, e.g. a generated config.
+    pub fn stdin(mut self, input: impl Into<Vec<u8>>) -> Self {
+        self.stdin = Some(input.into());
+        self
+    }
+
+    pub fn run(&self, toolchain: Option<&str>) -> WorkflowResult<()> {
+        self.run_cancellable(toolchain, &RunningProcess::new())?;
+        Ok(())
+    }
+
+    pub(crate) fn plan(&self) -> Vec<PlanStep> {
+        let env: Vec<_> = self
+            .env
+            .iter()
+            .filter_map(|(key, value)| match value {
+                EnvValue::Set(value) => Some((key.clone(), value.clone())),
+                EnvValue::Remove => None,
+            })
+            .chain(self.local_env.iter().cloned())
+            .collect();
+
+        let commands: Vec<&Cmd> = match &self.script {
+            RunEnum::Single(cmd) => vec![cmd],
+            RunEnum::Multi(cmds) => cmds.iter().collect(),
+        };
+
+        commands
+            .into_iter()
+            .map(|cmd| PlanStep::Command {
+                program: cmd.program.clone(),
+                args: cmd.args.clone(),
+                working_dir: self.directory.clone(),
+                env: env.clone(),
+            })
+            .collect()
+    }
+
+    /// Like [`Run::run`], but stops (returning `Ok(false)`) as soon as
+    /// `running` is cancelled, instead of starting any more commands.
+    pub(crate) fn run_cancellable(
+        &self,
+        toolchain: Option<&str>,
+        running: &RunningProcess,
+    ) -> WorkflowResult<bool> {
         let dir = self.directory.as_ref();
+        let stdin = self.stdin.as_deref();
 
         match &self.script {
-            RunEnum::Single(single) => single.run_in_dir(dir, is_nightly)?,
+            RunEnum::Single(single) => {
+                return single.run_in_dir_cancellable(
+                    dir,
+                    toolchain,
+                    &self.env,
+                    &self.local_env,
+                    stdin,
+                    running,
+                )
+            }
             RunEnum::Multi(multi) => {
-                for cmd in multi {
-                    cmd.run_in_dir(dir, is_nightly)?;
+                // In the generated YAML, a `script` is one shell invocation
+                // piped `stdin` as a whole; locally each line runs as its own
+                // process, so only the last one (the one a script typically
+                // reads its input in) gets `stdin`, matching `fmt_step`'s
+                // choice of where to attach the heredoc.
+                let last = multi.len().saturating_sub(1);
+
+                for (index, cmd) in multi.iter().enumerate() {
+                    let stdin = if index == last { stdin } else { None };
+
+                    if !cmd.run_in_dir_cancellable(
+                        dir,
+                        toolchain,
+                        &self.env,
+                        &self.local_env,
+                        stdin,
+                        running,
+                    )? {
+                        return Ok(false);
+                    }
                 }
             }
         }
 
-        Ok(())
+        Ok(true)
     }
 }
 
-impl fmt::Display for Run {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.write_str("    - ")?;
+impl Run {
+    fn fmt_step(&self, f: &mut fmt::Formatter<'_>, condition: Option<&str>) -> fmt::Result {
+        let mut prefix = "    - ";
+
+        if let Some(condition) = condition {
+            writeln!(f, "{prefix}if: {condition}")?;
+            prefix = "      ";
+        }
 
         if let Some(directory) = &self.directory {
-            writeln!(f, "working-directory: {directory}")?;
-            f.write_str("      ")?;
+            writeln!(f, "{prefix}working-directory: {directory}")?;
+            prefix = "      ";
+        }
+
+        let env: Vec<_> = self
+            .env
+            .iter()
+            .filter_map(|(key, value)| match value {
+                EnvValue::Set(value) => Some((key, value)),
+                EnvValue::Remove => None,
+            })
+            .collect();
+
+        if !env.is_empty() {
+            writeln!(f, "{prefix}env:")?;
+
+            for (key, value) in &env {
+                writeln!(f, "        {key}: {}", yaml_quote(value))?;
+            }
+
+            prefix = "      ";
         }
 
         match &self.script {
-            RunEnum::Single(cmd) => writeln!(f, "run: {cmd}")?,
-            RunEnum::Multi(multi) => {
-                f.write_str("run: |\n")?;
+            RunEnum::Single(cmd) => match &self.stdin {
+                Some(stdin) => {
+                    writeln!(f, "{prefix}run: |")?;
+                    writeln!(f, "        {cmd} <<'CI_STDIN'")?;
 
-                for cmd in multi {
-                    writeln!(f, "        {cmd}")?;
+                    for line in String::from_utf8_lossy(stdin).lines() {
+                        writeln!(f, "        {line}")?;
+                    }
+
+                    writeln!(f, "        CI_STDIN")?;
                 }
-            }
+                None => writeln!(f, "{prefix}run: {cmd}")?,
+            },
+            RunEnum::Multi(multi) => match &self.stdin {
+                Some(stdin) => {
+                    writeln!(f, "{prefix}run: |")?;
+
+                    // The whole script is one shell invocation, so it gets
+                    // one shared `stdin`, piped in via a heredoc appended to
+                    // its last line, matching `run_cancellable`'s choice of
+                    // which command locally receives it.
+                    let (init, last) = multi.split_at(multi.len().saturating_sub(1));
+
+                    for cmd in init {
+                        writeln!(f, "        {cmd}")?;
+                    }
+
+                    for cmd in last {
+                        writeln!(f, "        {cmd} <<'CI_STDIN'")?;
+                    }
+
+                    for line in String::from_utf8_lossy(stdin).lines() {
+                        writeln!(f, "        {line}")?;
+                    }
+
+                    writeln!(f, "        CI_STDIN")?;
+                }
+                None => {
+                    writeln!(f, "{prefix}run: |")?;
+
+                    for cmd in multi {
+                        writeln!(f, "        {cmd}")?;
+                    }
+                }
+            },
         }
 
         Ok(())
     }
 }
 
+/// Double-quote a YAML scalar, escaping characters that would otherwise end
+/// the string or change its meaning.
+fn yaml_quote(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+
+    for ch in value.chars() {
+        match ch {
+            '"' | '\\' => {
+                quoted.push('\\');
+                quoted.push(ch);
+            }
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            _ => quoted.push(ch),
+        }
+    }
+
+    quoted.push('"');
+    quoted
+}
+
 impl From<Run> for Step {
     fn from(value: Run) -> Self {
-        Self(StepEnum::Run(value))
+        Self::from_enum(StepEnum::Run(value))
     }
 }
 
@@ -434,11 +1075,23 @@ impl Cmd {
         }
     }
 
-    fn run_in_dir(&self, dir: Option<impl Into<PathBuf>>, is_nightly: bool) -> WorkflowResult<()> {
-        let cmd = if is_nightly {
+    fn run_in_dir_cancellable(
+        &self,
+        dir: Option<impl Into<PathBuf>>,
+        toolchain: Option<&str>,
+        env: &[(String, EnvValue)],
+        local_env: &[(String, String)],
+        stdin: Option<&[u8]>,
+        running: &RunningProcess,
+    ) -> WorkflowResult<bool> {
+        if running.is_cancelled() {
+            return Ok(false);
+        }
+
+        let cmd = if let Some(toolchain) = toolchain {
             duct::cmd(
                 "rustup",
-                ["run", "nightly", &self.program]
+                ["run", toolchain, &self.program]
                     .into_iter()
                     .chain(self.args.iter().map(|s| s.as_str())),
             )
@@ -446,14 +1099,39 @@ impl Cmd {
             duct::cmd(&self.program, &self.args)
         };
 
-        if let Some(dir) = dir {
+        let cmd = if let Some(dir) = dir {
             cmd.dir(dir)
         } else {
             cmd
+        };
+
+        let cmd = env.iter().fold(cmd, |cmd, (key, value)| match value {
+            EnvValue::Set(value) => cmd.env(key, value),
+            EnvValue::Remove => cmd.env_remove(key),
+        });
+
+        let cmd = local_env
+            .iter()
+            .fold(cmd, |cmd, (key, value)| cmd.env(key, value));
+
+        let cmd = match stdin {
+            Some(stdin) => cmd.stdin_bytes(stdin.to_vec()),
+            None => cmd,
+        };
+
+        let Some(handle) = running.start(cmd.start()?) else {
+            return Ok(false);
+        };
+
+        let result = handle.wait().map(|_| ());
+        running.finish();
+
+        if running.is_cancelled() {
+            return Ok(false);
         }
-        .run()?;
 
-        Ok(())
+        result?;
+        Ok(true)
     }
 }
 
@@ -469,6 +1147,60 @@ impl fmt::Display for Cmd {
     }
 }
 
+/// Tracks the `duct` process spawned for the currently-running [`Run`], so
+/// that a watcher (see `CI::watch`/`Tasks::watch`) can cancel it from another
+/// thread when a new file change comes in.
+pub(crate) struct RunningProcess {
+    handle: Mutex<Option<Arc<duct::Handle>>>,
+    cancelled: AtomicBool,
+}
+
+impl RunningProcess {
+    pub(crate) fn new() -> Self {
+        Self {
+            handle: Mutex::new(None),
+            cancelled: AtomicBool::new(false),
+        }
+    }
+
+    pub(crate) fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Kill the currently running process (if any), and stop any further
+    /// commands in this run from starting.
+    pub(crate) fn kill(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+
+        if let Some(handle) = self.handle.lock().unwrap().take() {
+            let _ = handle.kill();
+        }
+    }
+
+    fn start(&self, handle: duct::Handle) -> Option<Arc<duct::Handle>> {
+        // Hold the lock across the check, so a concurrent `kill` either is
+        // seen by the check or takes (and kills) the stored handle.
+        let mut slot = self.handle.lock().unwrap();
+
+        if self.is_cancelled() {
+            let _ = handle.kill();
+            return None;
+        }
+
+        let handle = Arc::new(handle);
+        *slot = Some(handle.clone());
+        Some(handle)
+    }
+
+    fn finish(&self) {
+        *self.handle.lock().unwrap() = None;
+    }
+}
+
+pub(crate) fn io_error(error: impl std::error::Error + Send + Sync + 'static) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, error)
+}
+
 impl<Arg, Args> From<Args> for Cmd
 where
     Arg: Into<String>,
@@ -487,6 +1219,6 @@ pub fn when(condition: bool, step: impl Into<Step>) -> Step {
     if condition {
         step.into()
     } else {
-        Step(StepEnum::Empty)
+        Step::from_enum(StepEnum::Empty)
     }
 }